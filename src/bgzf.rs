@@ -0,0 +1,323 @@
+//! BGZF block format: parsing and encoding.
+//!
+//! BGZF is the block-gzip format used by BAM/tabix-indexed files; it is a
+//! specialization of gzip (RFC 1952) where every member carries a `BC`
+//! FEXTRA subfield recording the member's own compressed size. See the SAM
+//! spec: https://samtools.github.io/hts-specs/SAMv1.pdf
+
+use std::io::{Result, Error, ErrorKind};
+
+use flate2::{Compress, Compression, FlushCompress, Status};
+use flate2::Crc;
+
+/// A BGZF block's uncompressed payload may not exceed this size, matching
+/// htslib's `bgzf.c` (`BGZF_BLOCK_SIZE`).
+pub const MAX_BLOCK_UNCOMPRESSED_SIZE: usize = 65280;
+
+pub const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff,
+    0x06, 0x00, 0x42, 0x43, 0x02, 0x00, 0x1b, 0x00, 0x03, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
+];
+
+pub struct BgzfHeader {
+    pub bsize: u16,
+}
+
+pub fn has_bgzf_eof_marker(buf: &[u8]) -> bool {
+    let mut buf_end: [u8; 28] = [0; 28];
+    buf_end.copy_from_slice(&buf[buf.len() - 28..]);
+
+    for i in 0..28 {
+        if BGZF_EOF[i] != buf_end[i] {
+            return false;
+        }
+    }
+    return true;
+}
+
+// SAM spec: https://samtools.github.io/hts-specs/SAMv1.pdf
+// Specifically, BGZF compresssion format (header), which is a
+// specialization of gzip RFC 1952
+pub fn parse_bgzf_header(buf: &[u8]) -> Result<BgzfHeader> {
+    const MIN_BGZF_HDR_SIZE: usize = 16;
+
+    if buf.len() < MIN_BGZF_HDR_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "input too small"));
+    }
+
+    if buf[0] != 31 || buf[1] != 139 {
+        return Err(Error::new(ErrorKind::InvalidData, "input does not start with gzip magic nums"));
+    }
+
+    if buf[2] != 8 {
+        return Err(Error::new(ErrorKind::InvalidData, "CM field in gzip header is invalid for a BGZF file"));
+    }
+
+    if buf[3] != 4 {
+        return Err(Error::new(ErrorKind::InvalidData, "FLGs field in gzip header invalid for a BGZF (BAM) file"));
+    }
+
+    let xlen: u16 = (buf[10] as u16) | ((buf[11] as u16) << 8);
+
+    const REQ_FIELDS_SIZE: usize = 12;
+    if (xlen as usize) + REQ_FIELDS_SIZE > buf.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "Not enough room left in data to accomodate FEXTRA fields"));
+    }
+
+    let mut off = REQ_FIELDS_SIZE as usize;
+    let end = off + (xlen as usize);
+    while off  < end {
+        const FEXTRA_FIELD_MIN_SZ: usize = 4;
+        if end - off < FEXTRA_FIELD_MIN_SZ {
+            return Err(Error::new(ErrorKind::InvalidData, "Ran out of data when reading FEXTRA field"))
+        }
+
+        let si1 = buf[off];
+        off += 1;
+        let si2 = buf[off];
+        off += 1;
+        let slen = (buf[off] as u16) | ((buf[off+1] as u16) << 8);
+        off += 2;
+
+        if off + (slen as usize) > end {
+            return Err(Error::new(ErrorKind::InvalidData, "Ran out of data when reading FEXTRA field: out of bounds slen field"));
+        }
+
+        if si1 == 66 && si2 == 67 && slen == 2 {
+            // it's the header we want
+            let bsize = (buf[off] as u16) | ((buf[off+1] as u16) << 8);
+            return Ok(BgzfHeader { bsize: bsize });
+        } else {
+            off += slen as usize;  // skip
+        }
+    }
+
+    return Err(Error::new(ErrorKind::InvalidData, "BC BGZF header not found in gzip Xtra flags"));
+}
+
+pub struct BgzfBlockPos {
+    pub offset: usize,
+    pub size: u32,
+}
+
+/// Walks every BGZF member in `buf`, recording its offset and compressed
+/// size. `buf` must already be known to end with the BGZF EOF marker.
+pub fn scan_blocks(buf: &[u8]) -> Result<Vec<BgzfBlockPos>> {
+    let mut blks = vec![];
+    let mut off: usize = 0;
+    while off < buf.len() {
+        let hdr = parse_bgzf_header(&buf[off..])?;
+        // BSIZE is `total_block_length - 1`, so a spec-legal BSIZE of
+        // 0xFFFF means a 65536-byte block -- one past what a u16 can hold.
+        let bsize = hdr.bsize as u32 + 1;
+        if off + (bsize as usize) > buf.len() {
+            return Err(Error::new(ErrorKind::InvalidData, format!(
+                "BGZF block at byte offset {} declares a BSIZE of {} bytes, which overruns the end of the input",
+                off, bsize
+            )));
+        }
+        blks.push(BgzfBlockPos {
+            offset: off,
+            size: bsize,
+        });
+        off += bsize as usize;
+    }
+    Ok(blks)
+}
+
+/// Reads a BGZF/gzip member's ISIZE (the uncompressed size of its payload,
+/// mod 2^32) directly from the last 4 bytes of the member, without having
+/// to inflate it.
+pub fn block_isize(block: &[u8]) -> u32 {
+    let n = block.len();
+    u32::from_le_bytes([block[n - 4], block[n - 3], block[n - 2], block[n - 1]])
+}
+
+/// Length, in bytes, of a BGZF member's header: the 10 fixed fields, the
+/// 2-byte XLEN and the XLEN bytes of FEXTRA. FLG is not consulted: the
+/// member is taken to carry FEXTRA and no other optional header fields, as
+/// every BGZF member does. The raw deflate payload starts immediately after
+/// this.
+pub fn header_len(buf: &[u8]) -> Result<usize> {
+    const REQ_FIELDS_SIZE: usize = 12;
+    if buf.len() < REQ_FIELDS_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "input too small to contain a gzip header"));
+    }
+
+    let xlen: u16 = (buf[10] as u16) | ((buf[11] as u16) << 8);
+    Ok(REQ_FIELDS_SIZE + xlen as usize)
+}
+
+/// Reads a gzip member's stored CRC32 (the 4 bytes preceding ISIZE in the
+/// trailer) directly, without having to inflate the member.
+pub fn trailer_crc32(block: &[u8]) -> u32 {
+    let n = block.len();
+    u32::from_le_bytes([block[n - 8], block[n - 7], block[n - 6], block[n - 5]])
+}
+
+/// Verifies that `decompressed` (the result of inflating BGZF block number
+/// `block_idx`, which starts at `block_offset` in the input) matches the
+/// block's own CRC32 and ISIZE trailer fields, so corruption is reported
+/// rather than silently passed through.
+pub fn verify_block(block_idx: usize, block_offset: usize, block: &[u8], decompressed: &[u8]) -> Result<()> {
+    let mut crc = Crc::new();
+    crc.update(decompressed);
+    let actual_crc = crc.sum();
+    let expected_crc = trailer_crc32(block);
+    if actual_crc != expected_crc {
+        return Err(Error::new(ErrorKind::InvalidData, format!(
+            "BGZF block {} at byte offset {}: CRC32 mismatch (expected {:#010x}, got {:#010x})",
+            block_idx, block_offset, expected_crc, actual_crc
+        )));
+    }
+
+    let expected_isize = block_isize(block);
+    let actual_isize = decompressed.len() as u32;
+    if actual_isize != expected_isize {
+        return Err(Error::new(ErrorKind::InvalidData, format!(
+            "BGZF block {} at byte offset {}: decompressed length {} does not match ISIZE {}",
+            block_idx, block_offset, actual_isize, expected_isize
+        )));
+    }
+
+    Ok(())
+}
+
+/// Raw-deflates `data` and wraps it in a BGZF member: a gzip header carrying
+/// the `BC` FEXTRA subfield (`BSIZE = total_block_length - 1`), the deflated
+/// payload, and the usual gzip CRC32/ISIZE trailer. Mirrors htslib's
+/// `bgzf_compress`. `data` must be no larger than
+/// `MAX_BLOCK_UNCOMPRESSED_SIZE`.
+pub fn compress_block(data: &[u8]) -> Result<Vec<u8>> {
+    if data.len() > MAX_BLOCK_UNCOMPRESSED_SIZE {
+        return Err(Error::new(ErrorKind::InvalidInput, "block exceeds max BGZF uncompressed block size"));
+    }
+
+    let mut deflated = Vec::with_capacity(data.len());
+    let mut compress = Compress::new(Compression::default(), false);
+    loop {
+        let consumed = compress.total_in() as usize;
+        let produced_before = deflated.len();
+        deflated.reserve(1024);
+        let status = compress
+            .compress_vec(&data[consumed..], &mut deflated, FlushCompress::Finish)
+            .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+        if status == Status::StreamEnd {
+            break;
+        }
+        if deflated.len() == produced_before && compress.total_in() as usize == consumed {
+            return Err(Error::new(ErrorKind::Other, "deflate stream made no progress"));
+        }
+    }
+
+    let mut crc = Crc::new();
+    crc.update(data);
+
+    const HEADER_SIZE: usize = 18;  // 10 fixed fields + XLEN(2) + BC subfield(6)
+    const TRAILER_SIZE: usize = 8;  // CRC32(4) + ISIZE(4)
+    let bsize = (HEADER_SIZE + deflated.len() + TRAILER_SIZE - 1) as u16;
+
+    let mut block = Vec::with_capacity(HEADER_SIZE + deflated.len() + TRAILER_SIZE);
+    block.extend_from_slice(&[0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff]);
+    block.extend_from_slice(&6u16.to_le_bytes());     // XLEN: just the BC subfield
+    block.extend_from_slice(&[66, 67]);                // SI1, SI2
+    block.extend_from_slice(&2u16.to_le_bytes());      // SLEN
+    block.extend_from_slice(&bsize.to_le_bytes());      // BSIZE
+    block.extend_from_slice(&deflated);
+    block.extend_from_slice(&crc.sum().to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+    Ok(block)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::{Decompress, FlushDecompress};
+
+    /// Inflates a block's raw deflate payload directly, mirroring
+    /// `compress_block`'s own `Compress` usage, so tests don't need to pull
+    /// in the gzip framing that lives over in `main.rs`.
+    fn inflate_payload(block: &[u8]) -> Result<Vec<u8>> {
+        let hdr_len = header_len(block)?;
+        let payload = &block[hdr_len..block.len() - 8];
+
+        let mut decompress = Decompress::new(false);
+        let mut out = Vec::new();
+        loop {
+            let consumed = decompress.total_in() as usize;
+            let produced_before = out.len();
+            out.reserve(1024);
+            let status = decompress
+                .decompress_vec(&payload[consumed..], &mut out, FlushDecompress::Finish)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+            if status == Status::StreamEnd {
+                break;
+            }
+            if out.len() == produced_before && decompress.total_in() as usize == consumed {
+                return Err(Error::new(ErrorKind::InvalidData, "inflate made no progress"));
+            }
+        }
+        Ok(out)
+    }
+
+    #[test]
+    fn verify_block_accepts_an_untouched_block() {
+        let original: &[u8] = b"round trip";
+        let block = compress_block(original).unwrap();
+        let data = inflate_payload(&block).unwrap();
+        assert!(verify_block(0, 0, &block, &data).is_ok());
+    }
+
+    #[test]
+    fn verify_block_rejects_a_flipped_byte() {
+        let original: &[u8] = b"hello bgzf world, this payload is long enough to give deflate some real structure to compress";
+        let mut block = compress_block(original).unwrap();
+
+        let hdr_len = header_len(&block).unwrap();
+        let payload_end = block.len() - 8;
+        assert!(payload_end > hdr_len + 1, "test payload too small to have a flippable byte");
+        let flip_at = hdr_len + (payload_end - hdr_len) / 2;
+        block[flip_at] ^= 0xff;
+
+        // Mirrors what handle_input's worker closure does: inflate, then
+        // verify against the block's own CRC32/ISIZE trailer. Either the
+        // corrupted deflate stream itself fails to inflate, or it inflates
+        // to the wrong bytes and verify_block's CRC32 check catches it --
+        // either way this must come back as an Err, not a panic.
+        let result = inflate_payload(&block).and_then(|data| verify_block(0, 0, &block, &data));
+        assert!(result.is_err(), "corrupted block should be rejected, not silently accepted");
+    }
+
+    #[test]
+    fn scan_blocks_rejects_a_block_whose_bsize_overruns_the_buffer() {
+        let block = compress_block(b"hello").unwrap();
+        let truncated = &block[..block.len() - 1];
+
+        assert!(scan_blocks(truncated).is_err(), "a block overrunning the buffer should be rejected, not panic later when sliced");
+    }
+
+    #[test]
+    fn scan_blocks_handles_the_max_legal_bsize_without_overflow() {
+        // BSIZE = 0xFFFF is spec-legal -- a full 65536-byte block -- one
+        // past what the on-disk u16 BSIZE field plus 1 can hold in a u16
+        // `total_block_length`.
+        let mut block = vec![0u8; 12];
+        block[0] = 0x1f;
+        block[1] = 0x8b;
+        block[2] = 0x08;
+        block[3] = 0x04;
+        block[10..12].copy_from_slice(&6u16.to_le_bytes()); // XLEN
+        block.extend_from_slice(&[66, 67]);                  // SI1, SI2
+        block.extend_from_slice(&2u16.to_le_bytes());        // SLEN
+        block.extend_from_slice(&0xFFFFu16.to_le_bytes());   // BSIZE
+        // Pad out to the full declared block length (BSIZE + 1 = 65536).
+        block.resize(65536, 0);
+
+        let blks = scan_blocks(&block).unwrap();
+        assert_eq!(blks.len(), 1);
+        assert_eq!(blks[0].size, 65536);
+    }
+}
@@ -0,0 +1,93 @@
+//! htslib-compatible `.gzi` index.
+//!
+//! A `.gzi` file is a little-endian `u64` entry count followed by that many
+//! `(compressed_offset, uncompressed_offset)` `u64` pairs, one per BGZF block
+//! after the first (block 0 is implicitly `(0, 0)`). It lets a BGZF virtual
+//! offset be resolved to its owning block without scanning the whole file.
+
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Result, Error, ErrorKind};
+use std::path::Path;
+
+use crate::bgzf::{self, BgzfBlockPos};
+
+pub struct GziIndex {
+    entries: Vec<(u64, u64)>,
+}
+
+impl GziIndex {
+    /// Builds the index from the blocks `scan_blocks` already found in
+    /// `buf`, reading each block's ISIZE straight from its trailer. The
+    /// trailing BGZF EOF marker is itself a (empty) BGZF block, but htslib
+    /// doesn't index it, so it's excluded here too.
+    pub fn build(blks: &[BgzfBlockPos], buf: &[u8]) -> GziIndex {
+        let blks = match blks.last() {
+            Some(last) if buf[last.offset..last.offset + last.size as usize] == bgzf::BGZF_EOF[..] => {
+                &blks[..blks.len() - 1]
+            }
+            _ => blks,
+        };
+
+        let mut entries = Vec::with_capacity(blks.len().saturating_sub(1));
+        let mut compressed_offset: u64 = 0;
+        let mut uncompressed_offset: u64 = 0;
+        for (i, blk) in blks.iter().enumerate() {
+            let block_data = &buf[blk.offset..blk.offset + blk.size as usize];
+            compressed_offset += blk.size as u64;
+            uncompressed_offset += bgzf::block_isize(block_data) as u64;
+            if i + 1 < blks.len() {
+                entries.push((compressed_offset, uncompressed_offset));
+            }
+        }
+        GziIndex { entries }
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut out = Vec::with_capacity(8 + self.entries.len() * 16);
+        out.extend_from_slice(&(self.entries.len() as u64).to_le_bytes());
+        for (compressed_offset, uncompressed_offset) in &self.entries {
+            out.extend_from_slice(&compressed_offset.to_le_bytes());
+            out.extend_from_slice(&uncompressed_offset.to_le_bytes());
+        }
+        fs::write(path, out)
+    }
+
+    pub fn read(path: &Path) -> Result<GziIndex> {
+        let raw = fs::read(path)?;
+        if raw.len() < 8 {
+            return Err(Error::new(ErrorKind::InvalidData, "gzi index is too small to contain an entry count"));
+        }
+
+        let num_entries = u64::from_le_bytes(raw[0..8].try_into().unwrap()) as usize;
+        if raw.len() != 8 + num_entries * 16 {
+            return Err(Error::new(ErrorKind::InvalidData, "gzi index length does not match its entry count"));
+        }
+
+        let mut entries = Vec::with_capacity(num_entries);
+        for i in 0..num_entries {
+            let off = 8 + i * 16;
+            let compressed_offset = u64::from_le_bytes(raw[off..off + 8].try_into().unwrap());
+            let uncompressed_offset = u64::from_le_bytes(raw[off + 8..off + 16].try_into().unwrap());
+            entries.push((compressed_offset, uncompressed_offset));
+        }
+
+        Ok(GziIndex { entries })
+    }
+
+    /// Resolves a BGZF virtual offset (`compressed_block_offset << 16 |
+    /// offset_within_uncompressed_block`) to the owning block's compressed
+    /// file offset and the byte offset to skip to in its decompressed
+    /// output, confirming against the index that the block offset really is
+    /// a block boundary rather than an arbitrary compressed byte.
+    pub fn locate(&self, voffset: u64) -> Result<(u64, usize)> {
+        let block_offset = voffset >> 16;
+        let intra_block_offset = (voffset & 0xffff) as usize;
+
+        if block_offset == 0 || self.entries.binary_search_by_key(&block_offset, |&(c, _)| c).is_ok() {
+            Ok((block_offset, intra_block_offset))
+        } else {
+            Err(Error::new(ErrorKind::InvalidInput, "virtual offset does not point at a known BGZF block boundary"))
+        }
+    }
+}
@@ -1,11 +1,17 @@
 extern crate clap;
 extern crate memmap;
 extern crate flate2;
+#[cfg(feature = "libdeflate")]
+extern crate libdeflater;
 
-use std::path::Path;
+mod bgzf;
+mod gzi;
+
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::io;
-use std::io::{Result, Error, ErrorKind};
+use std::io::{Result, Error, ErrorKind, Read};
+use std::num::NonZeroUsize;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc};
 use std::thread;
@@ -14,109 +20,56 @@ use std::sync::mpsc::{sync_channel, Receiver};
 
 use clap::{Arg, App};
 use memmap::{MmapOptions, Mmap};
+#[cfg(not(feature = "libdeflate"))]
 use flate2::bufread::GzDecoder;
+use flate2::bufread::MultiGzDecoder;
 
+use bgzf::MAX_BLOCK_UNCOMPRESSED_SIZE;
+use gzi::GziIndex;
 
-struct BgzfHeader {
-    bsize: u16,
-}
-
-fn has_bgzf_eof_marker(buf: &[u8]) -> bool {
-    const BGZF_EOF: [u8; 28] = [
-        0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff,
-        0x06, 0x00, 0x42, 0x43, 0x02, 0x00, 0x1b, 0x00, 0x03, 0x00,
-        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00
-    ];
-    
-    let mut buf_end: [u8; 28] = [0; 28];
-    buf_end.copy_from_slice(&buf[buf.len() - 28..]);
-
-    for i in 0..28 {
-        if BGZF_EOF[i] != buf_end[i] {
-            return false;
-        }
-    }
-    return true;
-}
-
-// SAM spec: https://samtools.github.io/hts-specs/SAMv1.pdf
-// Specifically, BGZF compresssion format (header), which is a
-// specialization of gzip RFC 1952
-fn parse_bgzf_header(buf: &[u8]) -> Result<BgzfHeader> {
-    const MIN_BGZF_HDR_SIZE: usize = 16;
-    
-    if buf.len() < MIN_BGZF_HDR_SIZE {
-        return Err(Error::new(ErrorKind::InvalidData, "input too small"));
-    }
-    
-    if buf[0] != 31 || buf[1] != 139 {
-        return Err(Error::new(ErrorKind::InvalidData, "input does not start with gzip magic nums"));
-    }
-
-    if buf[2] != 8 {
-        return Err(Error::new(ErrorKind::InvalidData, "CM field in gzip header is invalid for a BGZF file"));
-    }
+/// Inflates a single BGZF/gzip member by streaming it through `flate2`. This
+/// is the portable default; see the `libdeflate`-feature override below for
+/// the one-shot alternative.
+#[cfg(not(feature = "libdeflate"))]
+fn decompress_gz(buf: &[u8]) -> Result<Vec<u8>> {
+    let mut gz = GzDecoder::new(buf);
+    let mut out = vec![];
 
-    if buf[3] != 4 {
-        return Err(Error::new(ErrorKind::InvalidData, "FLGs field in gzip header invalid for a BGZF (BAM) file"));
-    }
+    io::copy(&mut gz, &mut out)?;
 
-    let xlen: u16 = (buf[10] as u16) | ((buf[11] as u16) << 8);    
+    Ok(out)
+}
 
-    const REQ_FIELDS_SIZE: usize = 12;
-    if (xlen as usize) + REQ_FIELDS_SIZE > buf.len() {
-        return Err(Error::new(ErrorKind::InvalidData, "Not enough room left in data to accomodate FEXTRA fields"));
+/// Inflates a single BGZF/gzip member in one shot via libdeflate: every BGZF
+/// member already stores its exact uncompressed size in its ISIZE trailer,
+/// so the output buffer can be preallocated and handed straight to
+/// `libdeflate_deflate_decompress` instead of growing it incrementally.
+#[cfg(feature = "libdeflate")]
+fn decompress_gz(buf: &[u8]) -> Result<Vec<u8>> {
+    let isize_ = bgzf::block_isize(buf) as usize;
+    let header_len = bgzf::header_len(buf)?;
+    const TRAILER_SIZE: usize = 8;  // CRC32(4) + ISIZE(4)
+    if header_len + TRAILER_SIZE > buf.len() {
+        return Err(Error::new(ErrorKind::InvalidData, "gzip member too small to contain its header and trailer"));
     }
+    let payload = &buf[header_len..buf.len() - TRAILER_SIZE];
 
-    let mut off = REQ_FIELDS_SIZE as usize;
-    let end = off + (xlen as usize);
-    while off  < end {
-        const FEXTRA_FIELD_MIN_SZ: usize = 4;
-        if end - off < FEXTRA_FIELD_MIN_SZ {
-            return Err(Error::new(ErrorKind::InvalidData, "Ran out of data when reading FEXTRA field"))
-        }
-
-        let si1 = buf[off];
-        off += 1;
-        let si2 = buf[off];
-        off += 1;
-        let slen = (buf[off] as u16) | ((buf[off+1] as u16) << 8);
-        off += 2;
-
-        if off + (slen as usize) > end {
-            return Err(Error::new(ErrorKind::InvalidData, "Ran out of data when reading FEXTRA field: out of bounds slen field"));
-        }
+    let mut out = vec![0u8; isize_];
+    let mut decompressor = libdeflater::Decompressor::new();
+    let n = decompressor
+        .deflate_decompress(payload, &mut out)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("libdeflate decompression failed: {}", e)))?;
 
-        if si1 == 66 && si2 == 67 && slen == 2 {
-            // it's the header we want
-            let bsize = (buf[off] as u16) | ((buf[off+1] as u16) << 8);
-            return Ok(BgzfHeader { bsize: bsize });
-        } else {
-            off += slen as usize;  // skip
-        }
+    if n != isize_ {
+        return Err(Error::new(ErrorKind::InvalidData, format!("libdeflate decompressed {} bytes but ISIZE says {}", n, isize_)));
     }
-    
-    return Err(Error::new(ErrorKind::InvalidData, "BC BGZF header not found in gzip Xtra flags"));
-}
-
-fn decompress_gz(buf: &[u8]) -> Result<Vec<u8>> {
-    let mut gz = GzDecoder::new(buf);
-    let mut out = vec![];
 
-    io::copy(&mut gz, &mut out)?;
-    
     Ok(out)
 }
 
-struct BgzfBlockPos {
-    offset: usize,
-    size: u16,
-}
-
-// todo: error results...
 struct WorkerOutput {
     idx: usize,
-    data: Vec<u8>,
+    data: Result<Vec<u8>>,
 }
 
 struct Worker {
@@ -124,59 +77,35 @@ struct Worker {
     output: Receiver<WorkerOutput>,
 }
 
-fn handle_input(buf: Mmap) -> Result<()> {
-    const BGZF_MIN_SZ: usize = 28;
-    if buf.len() < BGZF_MIN_SZ {
-        return Err(Error::new(ErrorKind::InvalidData, "Input data is too small for a bam file. A bam file is *at least* 28 bytes long (i.e. an EOF marker)"));
-    }
-
-    if !has_bgzf_eof_marker(&buf[..]) {
-        return Err(Error::new(ErrorKind::InvalidData, "Input missing bgzf EOF marker"));
-    }    
-    
-    let blks = {
-        let mut blks = vec![];
-        let mut off: usize  = 0;    
-        while off < buf.len() {
-            let hdr = parse_bgzf_header(&buf[off..])?;
-            let bsize = hdr.bsize + 1;
-            // todo: assert bsize in buf bounds
-            blks.push(BgzfBlockPos {
-                offset: off,
-                size: bsize,
-            });
-            off += bsize as usize;
-        }
-        blks
-    };
-
-    // Shared between threads to distribute work
-    let num_blks = blks.len();
-    let buf = Arc::new(buf);
-    let blks = Arc::new(blks);
+/// Runs `num_items` independent units of work (indices `0..num_items`)
+/// across `num_workers` threads, each pulling the next unclaimed index from
+/// a shared counter, then reassembles their outputs on the calling thread in
+/// ascending index order via a bounded, index-ordered merge of the workers'
+/// output queues. `work` must be index-independent of output order; `emit`
+/// is called once per item, in order, on the calling thread.
+fn fan_out_in_order<W, E>(num_workers: usize, num_items: usize, work: W, mut emit: E) -> Result<()>
+where
+    W: Fn(usize) -> Result<Vec<u8>> + Send + Sync + 'static,
+    E: FnMut(usize, Vec<u8>) -> Result<()>,
+{
+    let buf_size = 8 * num_workers;
+    let work = Arc::new(work);
     let in_idx = Arc::new(AtomicUsize::new(0));
 
-    // These two can heavily affect thread balancing.
-    const NUM_WORKERS: usize = 11;
-    const BUF_SIZE: usize = 8 * NUM_WORKERS;
-    
-    let mut workers = vec![];    
-    for _ in 0..NUM_WORKERS {
-        let buf = Arc::clone(&buf);
-        let blks = Arc::clone(&blks);
+    let mut workers = vec![];
+    for _ in 0..num_workers {
+        let work = Arc::clone(&work);
         let in_idx = Arc::clone(&in_idx);
-        let (tx, rx) = sync_channel(BUF_SIZE);
+        let (tx, rx) = sync_channel(buf_size);
 
         let jh = thread::spawn(move || {
             loop {
                 let v = (*in_idx).fetch_add(1, Ordering::Relaxed);
-                if v >= num_blks {
+                if v >= num_items {
                     break;
                 }
-                let input = (*blks).get(v).unwrap();
-                let block_data = &buf[input.offset..input.offset+(input.size as usize)];
-                let data = decompress_gz(block_data).unwrap();
-                
+                let data = work(v);
+
                 tx.send(WorkerOutput {
                     idx: v,
                     data: data
@@ -197,19 +126,17 @@ fn handle_input(buf: Mmap) -> Result<()> {
 
     // Main thread is responsible for emitting the outputs in-order
     let mut cur_idx = 0;
-    loop {        
-        if cur_idx >= blks.len() {
+    loop {
+        if cur_idx >= num_items {
             break;
         }
-        
+
         for peek in peeks.iter_mut() {
-            if let Some(output) = peek.peek() {
-                if output.idx == cur_idx {
-                    let mut s = &output.data[..];
-                    io::copy(&mut s, &mut io::stdout())?;
-                    peek.next();  // dequeue it
-                    cur_idx += 1;
-                }
+            let matches_cur_idx = peek.peek().map_or(false, |output| output.idx == cur_idx);
+            if matches_cur_idx {
+                let output = peek.next().unwrap();  // dequeue it
+                emit(cur_idx, output.data?)?;
+                cur_idx += 1;
             }
         }
     }
@@ -221,20 +148,159 @@ fn handle_input(buf: Mmap) -> Result<()> {
     Ok(())
 }
 
+/// Options controlling how `handle_input` walks and emits a BGZF stream.
+struct DecompressOpts {
+    /// Byte offset of the first BGZF block to decode; non-zero when seeking
+    /// to a virtual offset that resolved to a block further into the file.
+    start_offset: usize,
+    /// Bytes to drop from the start of the first block's decompressed
+    /// output, i.e. the intra-block offset of a virtual offset seek target.
+    skip_bytes: usize,
+    /// When set, write a `.gzi` index covering the blocks decoded here.
+    write_index: Option<PathBuf>,
+}
+
+fn handle_input(buf: Mmap, opts: DecompressOpts, num_workers: usize) -> Result<()> {
+    if bgzf::parse_bgzf_header(&buf[opts.start_offset..]).is_err() {
+        // No `BC` FEXTRA subfield on the first member: this isn't BGZF, so
+        // none of its block-level tricks (parallel decode, random access)
+        // apply. Fall back to decoding it as a plain, possibly
+        // multi-member, gzip stream instead of rejecting it outright. Note
+        // this also catches inputs too small to hold a gzip header at all.
+        return handle_plain_gzip_input(buf, opts.skip_bytes);
+    }
+
+    // Only a genuine BGZF member reaches here, so it's safe to require the
+    // BGZF-specific 28-byte EOF marker.
+    const BGZF_MIN_SZ: usize = 28;
+    if buf.len() < BGZF_MIN_SZ {
+        return Err(Error::new(ErrorKind::InvalidData, "Input data is too small for a bam file. A bam file is *at least* 28 bytes long (i.e. an EOF marker)"));
+    }
+
+    if !bgzf::has_bgzf_eof_marker(&buf[..]) {
+        return Err(Error::new(ErrorKind::InvalidData, "Input missing bgzf EOF marker"));
+    }
+
+    let blks = bgzf::scan_blocks(&buf[opts.start_offset..])?;
+
+    if let Some(index_path) = &opts.write_index {
+        GziIndex::build(&blks, &buf[opts.start_offset..]).write(index_path)?;
+    }
+
+    let num_blks = blks.len();
+    let start_offset = opts.start_offset;
+    let skip_bytes = opts.skip_bytes;
+    let buf = Arc::new(buf);
+    let blks = Arc::new(blks);
+
+    fan_out_in_order(num_workers, num_blks, move |v| {
+        let input = blks.get(v).unwrap();
+        let block_start = start_offset + input.offset;
+        let block_data = &buf[block_start..block_start+(input.size as usize)];
+        decompress_gz(block_data).and_then(|data| {
+            bgzf::verify_block(v, block_start, block_data, &data)?;
+            Ok(data)
+        })
+    }, |idx, data| {
+        let skip = if idx == 0 { skip_bytes } else { 0 };
+        if skip > data.len() {
+            return Err(Error::new(ErrorKind::InvalidInput, format!(
+                "virtual offset's intra-block offset of {} is past the end of block {}, which only decompresses to {} bytes",
+                skip, idx, data.len()
+            )));
+        }
+        let mut s = &data[skip..];
+        io::copy(&mut s, &mut io::stdout())?;
+        Ok(())
+    })
+}
+
+/// Decodes a plain (non-BGZF) gzip stream, restarting the inflate engine at
+/// each member boundary the way libarchive's gzip filter does, so
+/// concatenated multi-member streams come out fully decompressed. Run
+/// single-threaded on the main thread: without BGZF's `BC` subfield, member
+/// boundaries aren't known up front, so there is nothing to fan out.
+fn handle_plain_gzip_input(buf: Mmap, skip_bytes: usize) -> Result<()> {
+    let mut gz = MultiGzDecoder::new(&buf[..]);
+    if skip_bytes > 0 {
+        io::copy(&mut (&mut gz).take(skip_bytes as u64), &mut io::sink())?;
+    }
+    io::copy(&mut gz, &mut io::stdout())?;
+    Ok(())
+}
+
+/// Extracts a single region from a BGZF file by jumping straight to the
+/// block that owns `voffset` (a BGZF virtual offset) via its `.gzi` index,
+/// rather than decoding every preceding block.
+fn handle_seek(buf: Mmap, gzi_path: &Path, voffset: u64, num_workers: usize) -> Result<()> {
+    let index = GziIndex::read(gzi_path)?;
+    let (block_offset, intra_block_offset) = index.locate(voffset)?;
+
+    handle_input(buf, DecompressOpts {
+        start_offset: block_offset as usize,
+        skip_bytes: intra_block_offset,
+        write_index: None,
+    }, num_workers)
+}
+
+/// Compresses `buf` into a BGZF stream: `buf` is split into chunks of at
+/// most `MAX_BLOCK_UNCOMPRESSED_SIZE` bytes, each chunk is deflated into its
+/// own BGZF block in parallel, and the blocks are written to stdout in
+/// order, followed by the BGZF EOF marker.
+fn handle_compress_input(buf: Mmap, num_workers: usize) -> Result<()> {
+    let num_chunks = if buf.is_empty() {
+        0
+    } else {
+        (buf.len() + MAX_BLOCK_UNCOMPRESSED_SIZE - 1) / MAX_BLOCK_UNCOMPRESSED_SIZE
+    };
+
+    let buf = Arc::new(buf);
+
+    fan_out_in_order(num_workers, num_chunks, move |v| {
+        let start = v * MAX_BLOCK_UNCOMPRESSED_SIZE;
+        let end = std::cmp::min(start + MAX_BLOCK_UNCOMPRESSED_SIZE, buf.len());
+        bgzf::compress_block(&buf[start..end])
+    }, |_idx, data| {
+        let mut s = &data[..];
+        io::copy(&mut s, &mut io::stdout())?;
+        Ok(())
+    })?;
+
+    io::copy(&mut &bgzf::BGZF_EOF[..], &mut io::stdout())?;
+
+    Ok(())
+}
+
+/// Default worker count when `-@` is not given: the machine's available
+/// parallelism, falling back to a single thread if that can't be detected.
+fn default_num_workers() -> usize {
+    thread::available_parallelism().map(NonZeroUsize::get).unwrap_or(1)
+}
+
 fn main() {
     let args = App::new("bam2sam")
         .version("1.0")
         .arg(Arg::with_name("decompress")
              .short("d")
-             .help("decompress input")
-             .required(true))
+             .help("decompress input"))
         .arg(Arg::with_name("force")
              .short("f")
              .help("force writing to terminal"))
         .arg(Arg::with_name("stdout")
              .short("c")
-             .help("write on standard output, keep original files unchanged")
-             .required(true))
+             .help("write on standard output, keep original files unchanged"))
+        .arg(Arg::with_name("index")
+             .short("i")
+             .long("index")
+             .help("write a .gzi index (FILE.gzi) alongside a decompressed FILE"))
+        .arg(Arg::with_name("voffset")
+             .long("voffset")
+             .takes_value(true)
+             .help("seek to a BGZF virtual offset using FILE.gzi, instead of decompressing from the start"))
+        .arg(Arg::with_name("threads")
+             .short("@")
+             .takes_value(true)
+             .help("number of worker threads (default: available parallelism)"))
         .arg(Arg::with_name("FILE")
              .help("input BAM")
              .required(true))
@@ -251,10 +317,27 @@ fn main() {
         std::process::exit(1);
     }
 
+    let num_workers: usize = args.value_of("threads")
+        .map(|s| s.parse::<NonZeroUsize>().expect("-@ must be a positive integer").get())
+        .unwrap_or_else(default_num_workers);
+
     let file = File::open(pth).unwrap();
     let mmap = unsafe {
         MmapOptions::new().map(&file).unwrap()
     };
-    
-    handle_input(mmap).unwrap();
+
+    let gzi_path = pth.with_file_name(format!("{}.gzi", pth.file_name().unwrap().to_str().unwrap()));
+
+    if let Some(voffset) = args.value_of("voffset") {
+        let voffset: u64 = voffset.parse().expect("voffset must be an unsigned integer");
+        handle_seek(mmap, &gzi_path, voffset, num_workers).unwrap();
+    } else if args.is_present("decompress") {
+        handle_input(mmap, DecompressOpts {
+            start_offset: 0,
+            skip_bytes: 0,
+            write_index: if args.is_present("index") { Some(gzi_path) } else { None },
+        }, num_workers).unwrap();
+    } else {
+        handle_compress_input(mmap, num_workers).unwrap();
+    }
 }